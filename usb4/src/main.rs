@@ -1,5 +1,8 @@
 use coreboot_collector::sideband::Sideband;
+use embedded_hal::i2c::{Error, ErrorKind, ErrorType, I2c, NoAcknowledgeSource, Operation};
 use std::{
+    env,
+    fmt,
     fs,
     io,
     rc::Rc,
@@ -12,9 +15,11 @@ use std::{
 const IECS_CMD: u8 = 8;
 const IECS_DATA: u8 = 9;
 const MSG_OUT_RDATA: u8 = 18;
+const MSG_IN_RDATA: u8 = 19;
 
 const CMD_AFRR: u32 = 0x52524641;
 const CMD_AUTH: u32 = 0x48545541;
+const CMD_BLKR: u32 = 0x524b4c42;
 const CMD_BLKW: u32 = 0x574b4c42;
 const CMD_BOPS: u32 = 0x53504f42;
 const CMD_PCYC: u32 = 0x43594350;
@@ -104,6 +109,52 @@ impl Gpio {
     }
 }
 
+// Phase of an SMBus/I2C transaction, used to report exactly where a NAK occurred
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum I2cPhase {
+    Address,
+    Command,
+    Length,
+    Offset(u8),
+    Byte(usize),
+}
+
+impl fmt::Display for I2cPhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            I2cPhase::Address => write!(f, "address"),
+            I2cPhase::Command => write!(f, "command"),
+            I2cPhase::Length => write!(f, "length"),
+            I2cPhase::Offset(n) => write!(f, "offset byte {}", n),
+            I2cPhase::Byte(n) => write!(f, "byte {}", n),
+        }
+    }
+}
+
+// Modeled on the embassy-rp I2C driver's error type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum I2cError {
+    NoAcknowledge(I2cPhase),
+    ArbitrationLoss,
+    Timeout,
+    // Distinct from Timeout (a stuck SCL line): the device kept NAKing
+    // ack-polls and never finished its internal write cycle in time
+    WriteCycleTimeout,
+}
+
+impl fmt::Display for I2cError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            I2cError::NoAcknowledge(phase) => write!(f, "no acknowledge on {}", phase),
+            I2cError::ArbitrationLoss => write!(f, "arbitration loss"),
+            I2cError::Timeout => write!(f, "timeout"),
+            I2cError::WriteCycleTimeout => write!(f, "timed out waiting for write cycle to complete"),
+        }
+    }
+}
+
+impl std::error::Error for I2cError {}
+
 pub struct I2CBitbang {
     scl: Gpio,
     scl_config: u32,
@@ -132,10 +183,14 @@ impl I2CBitbang {
         Self { scl, scl_config, sda, sda_config, }
     }
 
+    // Hard coded to 5 us, which is half of the period 10 us for a frequency of 100 KHz
+    const HALF_PERIOD: time::Duration = time::Duration::from_micros(5);
+    // Allow a clock-stretching slave to hold SCL low for up to 1000 bit periods
+    const CLOCK_STRETCH_TIMEOUT: time::Duration = time::Duration::from_micros(2 * 5 * 1000);
+
     // Delay half half of period
     fn delay(&self) {
-        // Hard coded to 5 us, which is half of the period 10 us for a frequency of 100 KHz
-        thread::sleep(time::Duration::from_micros(5));
+        thread::sleep(Self::HALF_PERIOD);
     }
 
     // Pull SCL low
@@ -143,9 +198,18 @@ impl I2CBitbang {
         self.scl.enable_tx(true);
     }
 
-    // Release SCL, bus pulls it high
-    unsafe fn set_scl(&mut self) {
+    // Release SCL and wait for it to read high, since a slave stretching the
+    // clock will hold it low until it is ready for the next bit
+    unsafe fn set_scl(&mut self) -> Result<(), I2cError> {
         self.scl.enable_tx(false);
+
+        let start = time::Instant::now();
+        while !self.scl.get_rx() {
+            if start.elapsed() > Self::CLOCK_STRETCH_TIMEOUT {
+                return Err(I2cError::Timeout);
+            }
+        }
+        Ok(())
     }
 
     // Pull SDA low
@@ -159,74 +223,87 @@ impl I2CBitbang {
     }
 
     // SDA goes high to low while SCL is high
-    unsafe fn start(&mut self) {
+    unsafe fn start(&mut self) -> Result<(), I2cError> {
         self.set_sda();
-        self.set_scl();
+        self.set_scl()?;
         self.delay();
         self.clr_sda();
         self.delay();
         self.clr_scl();
         self.delay();
+        Ok(())
     }
 
     // SDA goes low to high while SCL is high
-    unsafe fn stop(&mut self) {
+    unsafe fn stop(&mut self) -> Result<(), I2cError> {
         self.clr_sda();
         self.delay();
-        self.set_scl();
+        self.set_scl()?;
         self.delay();
         self.set_sda();
         self.delay();
+        Ok(())
     }
 
     // SDA is set while SCL is pulsed
-    unsafe fn write_bit(&mut self, bit: bool) {
+    // When SDA is released (bit is 1), it is read back once SCL is high to
+    // detect another master (or a shorted bus) driving it low instead
+    unsafe fn write_bit(&mut self, bit: bool) -> Result<(), I2cError> {
         if bit {
             self.set_sda();
         } else {
             self.clr_sda();
         }
         self.delay();
-        self.set_scl();
+        self.set_scl()?;
         self.delay();
+        let lost_arbitration = bit && !self.sda.get_rx();
         self.clr_scl();
+        if lost_arbitration {
+            return Err(I2cError::ArbitrationLoss);
+        }
+        Ok(())
     }
 
     // SDA is read while SCL is pulsed
-    unsafe fn read_bit(&mut self) -> bool {
+    unsafe fn read_bit(&mut self) -> Result<bool, I2cError> {
         self.set_sda();
         self.delay();
-        self.set_scl();
+        self.set_scl()?;
         self.delay();
         let bit = self.sda.get_rx();
         self.clr_scl();
-        bit
+        Ok(bit)
     }
 
     // Start condition is optionally sent
     // 8 bits are written
     // 1 bit is read, low if ack, high if nack
-    pub unsafe fn write_byte(&mut self, byte: u8, start: bool) -> bool {
+    pub unsafe fn write_byte(&mut self, byte: u8, start: bool, phase: I2cPhase) -> Result<(), I2cError> {
         if start {
-            self.start();
+            self.start()?;
         }
         for i in (0..8).rev() {
-            self.write_bit(byte & (1 << i) != 0);
+            self.write_bit(byte & (1 << i) != 0)?;
+        }
+        if self.read_bit()? {
+            Err(I2cError::NoAcknowledge(phase))
+        } else {
+            Ok(())
         }
-        !self.read_bit()
     }
 
     // 8 bits are read
     // 1 bit is written, low if ack, high if nack
-    pub unsafe fn read_byte(&mut self, ack: bool) -> u8 {
+    pub unsafe fn read_byte(&mut self, ack: bool) -> Result<u8, I2cError> {
         let mut byte = 0;
         for i in (0..8).rev() {
-            if self.read_bit() {
+            if self.read_bit()? {
                 byte |= 1 << i;
             }
         }
-        self.write_bit(!ack);
-        byte
+        self.write_bit(!ack)?;
+        Ok(byte)
     }
 
     // Start condition
@@ -235,28 +312,27 @@ impl I2CBitbang {
     // Byte count is written
     // Bytes are written
     // Stop condition
-    pub unsafe fn smbus_block_write(&mut self, address: u8, command: u8, bytes: &[u8]) -> usize {
+    pub unsafe fn smbus_block_write(&mut self, address: u8, command: u8, bytes: &[u8]) -> Result<usize, I2cError> {
         // Only 32 bytes can be processed at a time
         if bytes.len() > 32 {
-            return 0;
+            return Ok(0);
         }
 
-        let mut count = 0;
-        if self.write_byte(address << 1, true) {
-            if self.write_byte(command, false) {
-                if self.write_byte(bytes.len() as u8, false) {
-                    for byte in bytes.iter() {
-                        if self.write_byte(*byte, false) {
-                            count += 1;
-                        } else {
-                            break;
-                        }
-                    }
-                }
-            }
+        let result = self.smbus_block_write_inner(address, command, bytes);
+        let stop_result = self.stop();
+        result?;
+        stop_result?;
+        Ok(bytes.len())
+    }
+
+    unsafe fn smbus_block_write_inner(&mut self, address: u8, command: u8, bytes: &[u8]) -> Result<(), I2cError> {
+        self.write_byte(address << 1, true, I2cPhase::Address)?;
+        self.write_byte(command, false, I2cPhase::Command)?;
+        self.write_byte(bytes.len() as u8, false, I2cPhase::Length)?;
+        for (i, byte) in bytes.iter().enumerate() {
+            self.write_byte(*byte, false, I2cPhase::Byte(i))?;
         }
-        self.stop();
-        count
+        Ok(())
     }
 
     // Start condition
@@ -266,22 +342,27 @@ impl I2CBitbang {
     // Byte count is read
     // Bytes are read
     // Stop condition
-    pub unsafe fn smbus_block_read(&mut self, address: u8, command: u8) -> Vec<u8> {
+    pub unsafe fn smbus_block_read(&mut self, address: u8, command: u8) -> Result<Vec<u8>, I2cError> {
+        let result = self.smbus_block_read_inner(address, command);
+        let stop_result = self.stop();
+        let bytes = result?;
+        stop_result?;
+        Ok(bytes)
+    }
+
+    unsafe fn smbus_block_read_inner(&mut self, address: u8, command: u8) -> Result<Vec<u8>, I2cError> {
+        self.write_byte(address << 1, true, I2cPhase::Address)?;
+        self.write_byte(command, false, I2cPhase::Command)?;
+        self.write_byte(address << 1 | 1, true, I2cPhase::Address)?;
+
         //TODO: use static buffer?
-        let mut bytes = Vec::new();
-        if self.write_byte(address << 1, true) {
-            if self.write_byte(command, false) {
-                if self.write_byte(address << 1 | 1, true) {
-                    let count = self.read_byte(true);
-                    for i in 0..count {
-                        let ack = i + 1 != count;
-                        bytes.push(self.read_byte(ack));
-                    }
-                }
-            }
+        let count = self.read_byte(true)?;
+        let mut bytes = Vec::with_capacity(count as usize);
+        for i in 0..count {
+            let ack = i + 1 != count;
+            bytes.push(self.read_byte(ack)?);
         }
-        self.stop();
-        bytes
+        Ok(bytes)
     }
 }
 
@@ -299,6 +380,83 @@ impl Drop for I2CBitbang {
     }
 }
 
+impl Error for I2cError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            I2cError::NoAcknowledge(_) => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Unknown),
+            I2cError::ArbitrationLoss => ErrorKind::ArbitrationLoss,
+            I2cError::Timeout => ErrorKind::Other,
+            I2cError::WriteCycleTimeout => ErrorKind::Other,
+        }
+    }
+}
+
+impl ErrorType for I2CBitbang {
+    type Error = I2cError;
+}
+
+// For each operation, whether it needs a fresh START: only the first
+// operation and any Read/Write direction change do, so adjacent
+// same-direction operations are concatenated into one continuous transfer
+// per the I2c::transaction contract
+fn transaction_starts(operations: &[Operation]) -> Vec<bool> {
+    let mut prev_is_read = None;
+    operations.iter().map(|operation| {
+        let is_read = matches!(operation, Operation::Read(_));
+        let start = prev_is_read != Some(is_read);
+        prev_is_read = Some(is_read);
+        start
+    }).collect()
+}
+
+// Adapter over the start/write_byte/read_byte/stop primitives so this bus can
+// be used with any driver written against the embedded-hal I2c trait
+impl I2c for I2CBitbang {
+    fn transaction(&mut self, address: u8, operations: &mut [Operation]) -> Result<(), Self::Error> {
+        unsafe {
+            let starts = transaction_starts(operations);
+            let result = (|| {
+                for (index, (operation, start)) in operations.iter_mut().zip(starts.iter().copied()).enumerate() {
+                    match operation {
+                        Operation::Read(buffer) => {
+                            if start {
+                                self.write_byte(address << 1 | 1, true, I2cPhase::Address)?;
+                            }
+                            // A following Read continues this transfer, so only
+                            // the last byte before a restart or STOP is NAKed
+                            let continues = starts.get(index + 1) == Some(&false);
+                            let len = buffer.len();
+                            for (i, byte) in buffer.iter_mut().enumerate() {
+                                let ack = i + 1 != len || continues;
+                                *byte = self.read_byte(ack)?;
+                            }
+                        }
+                        Operation::Write(bytes) => {
+                            if start {
+                                self.write_byte(address << 1, true, I2cPhase::Address)?;
+                            }
+                            for (i, byte) in bytes.iter().enumerate() {
+                                self.write_byte(*byte, false, I2cPhase::Byte(i))?;
+                            }
+                        }
+                    }
+                }
+                Ok(())
+            })();
+
+            // No operations means no START was ever issued; calling stop()
+            // anyway would put a spurious START/STOP blip on an idle bus
+            if !starts.iter().any(|&start| start) {
+                return result;
+            }
+
+            let stop_result = self.stop();
+            result?;
+            stop_result
+        }
+    }
+}
+
 pub struct Retimer {
     i2c: I2CBitbang,
     address: u8,
@@ -310,7 +468,8 @@ impl Retimer {
     }
 
     pub unsafe fn read(&mut self, reg: u8) -> Result<u32, String> {
-        let bytes = self.i2c.smbus_block_read(self.address, reg);
+        let bytes = self.i2c.smbus_block_read(self.address, reg)
+            .map_err(|err| format!("Retimer::read: {}", err))?;
         if bytes.len() == 4 {
             Ok(
                 bytes[0] as u32 |
@@ -330,11 +489,12 @@ impl Retimer {
             (data >> 16) as u8,
             (data >> 24) as u8,
         ];
-        let count = self.i2c.smbus_block_write(self.address, reg, &bytes);
-        if count == 4 {
+        let count = self.i2c.smbus_block_write(self.address, reg, &bytes)
+            .map_err(|err| format!("Retimer::write: {}", err))?;
+        if count == bytes.len() {
             Ok(())
         } else {
-            Err(format!("Retimer::write: wrote {} bytes instead of 4", count))
+            Err(format!("Retimer::write: wrote {} bytes instead of {}", count, bytes.len()))
         }
     }
 
@@ -367,31 +527,151 @@ impl Rom {
         Self { i2c, address }
     }
 
-    pub unsafe fn read(&mut self, offset: u16, length: u16) -> Vec<u8> {
+    pub unsafe fn read(&mut self, offset: u16, length: u16) -> Result<Vec<u8>, I2cError> {
+        let result = self.read_inner(offset, length);
+        let stop_result = self.i2c.stop();
+        let bytes = result?;
+        stop_result?;
+        Ok(bytes)
+    }
+
+    unsafe fn read_inner(&mut self, offset: u16, length: u16) -> Result<Vec<u8>, I2cError> {
+        self.i2c.write_byte(self.address << 1, true, I2cPhase::Address)?;
+        self.i2c.write_byte((offset >> 8) as u8, false, I2cPhase::Offset(0))?;
+        self.i2c.write_byte(offset as u8, false, I2cPhase::Offset(1))?;
+        self.i2c.write_byte(self.address << 1 | 1, true, I2cPhase::Address)?;
+
         let mut bytes = Vec::with_capacity(length as usize);
-        if self.i2c.write_byte(self.address << 1, true) {
-            if self.i2c.write_byte((offset >> 8) as u8, false) {
-                if self.i2c.write_byte(offset as u8, false) {
-                    if self.i2c.write_byte(self.address << 1 | 1, true) {
-                        for i in 0..length {
-                            let ack = i + 1 != length;
-                            bytes.push(self.i2c.read_byte(ack));
-                        }
-                    }
+        for i in 0..length {
+            let ack = i + 1 != length;
+            bytes.push(self.i2c.read_byte(ack)?);
+        }
+        Ok(bytes)
+    }
+
+    // Number of ACK-poll attempts to make while waiting out a page's internal write cycle
+    const WRITE_POLL_RETRIES: u32 = 1000;
+
+    // Split data at the EEPROM's physical page boundaries (not just page_size
+    // boundaries relative to the start of data) and write each page with
+    // STOP + ACK-polling in between, so the device's internal write cycle
+    // finishes before the next page is sent. If offset doesn't start on a
+    // page boundary, the first chunk is shortened to the rest of that page,
+    // since the device's internal address counter wraps within the page
+    // instead of continuing into the next one.
+    pub unsafe fn write(&mut self, offset: u16, data: &[u8], page_size: u16) -> Result<(), I2cError> {
+        assert!(page_size > 0, "Rom::write: page_size must be non-zero");
+
+        let mut offset = offset;
+        let mut remaining = data;
+        while !remaining.is_empty() {
+            let first_page_remainder = page_size - (offset % page_size);
+            let chunk_len = first_page_remainder.min(remaining.len() as u16) as usize;
+            let (page, rest) = remaining.split_at(chunk_len);
+
+            self.write_page(offset, page)?;
+            self.ack_poll()?;
+
+            offset += page.len() as u16;
+            remaining = rest;
+        }
+        Ok(())
+    }
+
+    unsafe fn write_page(&mut self, offset: u16, page: &[u8]) -> Result<(), I2cError> {
+        let result = self.write_page_inner(offset, page);
+        let stop_result = self.i2c.stop();
+        result?;
+        stop_result?;
+        Ok(())
+    }
+
+    unsafe fn write_page_inner(&mut self, offset: u16, page: &[u8]) -> Result<(), I2cError> {
+        self.i2c.write_byte(self.address << 1, true, I2cPhase::Address)?;
+        self.i2c.write_byte((offset >> 8) as u8, false, I2cPhase::Offset(0))?;
+        self.i2c.write_byte(offset as u8, false, I2cPhase::Offset(1))?;
+        for (i, byte) in page.iter().enumerate() {
+            self.i2c.write_byte(*byte, false, I2cPhase::Byte(i))?;
+        }
+        Ok(())
+    }
+
+    // Repeatedly issue START + address+W until the device acknowledges,
+    // indicating the previous page's internal write cycle has completed.
+    // A NAK just means the device is still busy and is retried; any other
+    // error (arbitration loss, a stuck SCL line) is a real bus fault and is
+    // reported immediately instead of being retried or masked.
+    unsafe fn ack_poll(&mut self) -> Result<(), I2cError> {
+        for _ in 0..Self::WRITE_POLL_RETRIES {
+            match self.i2c.write_byte(self.address << 1, true, I2cPhase::Address) {
+                Ok(()) => return self.i2c.stop(),
+                Err(I2cError::NoAcknowledge(_)) => {
+                    self.i2c.stop()?;
+                }
+                Err(err) => {
+                    let _ = self.i2c.stop();
+                    return Err(err);
                 }
             }
         }
-        self.i2c.stop();
-        bytes
+        Err(I2cError::WriteCycleTimeout)
     }
 }
 
-unsafe fn flash_retimer(retimer: &mut Retimer) -> Result<(), String> {
+// Detached-signature verification requires a config-supplied public key
+// (../models/lemp10/usb4-retimer.rom.pub, 32 raw bytes) and a signature
+// (../models/lemp10/usb4-retimer.rom.sig, 64 raw bytes). Missing provisioning
+// is a hard failure, not a silent fall-back to flashing unsigned, unless the
+// caller explicitly passes --allow-unsigned to opt out.
+fn verify_retimer_image(image: &[u8], allow_unsigned: bool) -> Result<(), String> {
+    let public_key_bytes = match fs::read("../models/lemp10/usb4-retimer.rom.pub") {
+        Ok(bytes) => bytes,
+        Err(err) => return unsigned_fallback(allow_unsigned, &format!(
+            "usb4-retimer.rom.pub is required to verify the image and could not be read: {}", err
+        )),
+    };
+    let sig_bytes = match fs::read("../models/lemp10/usb4-retimer.rom.sig") {
+        Ok(bytes) => bytes,
+        Err(err) => return unsigned_fallback(allow_unsigned, &format!(
+            "usb4-retimer.rom.sig is required to verify the image and could not be read: {}", err
+        )),
+    };
+
+    let public_key: [u8; 32] = public_key_bytes.try_into().map_err(|bytes: Vec<u8>| {
+        format!("usb4-retimer.rom.pub must be exactly 32 bytes, got {}", bytes.len())
+    })?;
+    let verifying_key = ed25519_dalek::VerifyingKey::from_bytes(&public_key).map_err(|err| {
+        format!("invalid retimer public key: {}", err)
+    })?;
+
+    let signature = ed25519_dalek::Signature::from_slice(&sig_bytes).map_err(|err| {
+        format!("usb4-retimer.rom.sig is not a valid ed25519 signature: {}", err)
+    })?;
+
+    verifying_key.verify_strict(image, &signature).map_err(|err| {
+        format!("usb4-retimer.rom failed signature verification: {}", err)
+    })
+}
+
+// Refuse to flash an unverified image unless the caller explicitly opted out
+fn unsigned_fallback(allow_unsigned: bool, reason: &str) -> Result<(), String> {
+    if allow_unsigned {
+        eprintln!("WARNING: {}; flashing UNSIGNED image (--allow-unsigned)", reason);
+        Ok(())
+    } else {
+        Err(format!("{} (pass --allow-unsigned to flash anyway)", reason))
+    }
+}
+
+unsafe fn flash_retimer(retimer: &mut Retimer, allow_unsigned: bool) -> Result<(), String> {
     eprintln!("Vendor: {:X}", retimer.read(0)?);
     eprintln!("Device: {:X}", retimer.read(1)?);
 
     let image = fs::read("../models/lemp10/usb4-retimer.rom").unwrap();
 
+    eprintln!("Verify signature");
+    verify_retimer_image(&image, allow_unsigned)?;
+
     eprintln!("Set offset to 0");
     retimer.write(IECS_DATA, 0).unwrap();
     let status = retimer.command(CMD_BOPS);
@@ -426,6 +706,44 @@ unsafe fn flash_retimer(retimer: &mut Retimer) -> Result<(), String> {
     }
     eprintln!("\rWrite {}/{}", i, image.len());
 
+    eprintln!("Set offset to 0");
+    retimer.write(IECS_DATA, 0).unwrap();
+    let status = retimer.command(CMD_BOPS);
+    match status {
+        Err(why) => panic!("Failed to set offset: {}", why),
+	Ok(()) => {},
+    }
+
+    let mut i = 0;
+    while i < image.len() {
+        eprint!("\rVerify {}/{}", i, image.len());
+
+        let status = retimer.command(CMD_BLKR);
+        match status {
+            Err(why) => panic!("Failed to read back block at {:X}: {}", i, why),
+	    Ok(()) => {},
+        }
+
+        let mut j = 0;
+        while i < image.len() && j < 64 {
+            let expected =
+                image[i] as u32 |
+                (image[i + 1] as u32) << 8 |
+                (image[i + 2] as u32) << 16 |
+                (image[i + 3] as u32) << 24;
+            let actual = retimer.read(MSG_IN_RDATA)?;
+            if actual != expected {
+                return Err(format!(
+                    "Verification failed at offset 0x{:X}: expected 0x{:X}, read 0x{:X}",
+                    i, expected, actual
+                ));
+            }
+            i += 4;
+            j += 4;
+        }
+    }
+    eprintln!("\rVerify {}/{}", i, image.len());
+
     eprintln!("Authenticate");
     let status = retimer.command(CMD_AUTH);
     match status {
@@ -445,9 +763,9 @@ unsafe fn flash_retimer(retimer: &mut Retimer) -> Result<(), String> {
     Ok(())
 }
 
-unsafe fn retimer_access(i2c: I2CBitbang, address: u8) -> i32 {
+unsafe fn retimer_access(i2c: I2CBitbang, address: u8, allow_unsigned: bool) -> i32 {
     let mut retimer = Retimer::new(i2c, address);
-    match flash_retimer(&mut retimer) {
+    match flash_retimer(&mut retimer, allow_unsigned) {
         Ok(()) => 0,
         Err(err) => {
             eprintln!("Failed to flash retimer: {}", err);
@@ -457,25 +775,48 @@ unsafe fn retimer_access(i2c: I2CBitbang, address: u8) -> i32 {
 }
 
 unsafe fn flash_rom(rom: &mut Rom) -> Result<(), String> {
-    let data = rom.read(0, 32768);
+    let data = rom.read(0, 32768).map_err(|err| {
+        format!("failed to read usb4-pd.rom: {}", err)
+    })?;
     fs::write("usb4-pd.rom", &data).map_err(|err| {
         format!("failed to write usb4-pd.rom: {}", err)
     })?;
     Ok(())
 }
 
-unsafe fn rom_access(i2c: I2CBitbang, address: u8) -> i32 {
+// Page size of the PD controller's I2C EEPROM
+const ROM_PAGE_SIZE: u16 = 16;
+
+unsafe fn restore_rom(rom: &mut Rom) -> Result<(), String> {
+    let data = fs::read("usb4-pd.rom").map_err(|err| {
+        format!("failed to read usb4-pd.rom: {}", err)
+    })?;
+    rom.write(0, &data, ROM_PAGE_SIZE).map_err(|err| {
+        format!("failed to write usb4-pd.rom: {}", err)
+    })
+}
+
+enum RomMode {
+    Dump,
+    Restore,
+}
+
+unsafe fn rom_access(i2c: I2CBitbang, address: u8, mode: RomMode) -> i32 {
     let mut rom = Rom::new(i2c, address);
-    match flash_rom(&mut rom) {
+    let result = match mode {
+        RomMode::Dump => flash_rom(&mut rom),
+        RomMode::Restore => restore_rom(&mut rom),
+    };
+    match result {
         Ok(()) => 0,
         Err(err) => {
-            eprintln!("Failed to flash rom: {}", err);
+            eprintln!("Failed to access rom: {}", err);
             1
         }
     }
 }
 
-unsafe fn i2c_access(sideband: Rc<Sideband>) -> i32 {
+unsafe fn i2c_access(sideband: Rc<Sideband>, allow_unsigned: bool) -> i32 {
     enum I2CBus {
         I2C1,
         SMLink0,
@@ -501,11 +842,12 @@ unsafe fn i2c_access(sideband: Rc<Sideband>) -> i32 {
         },
     };
 
-    retimer_access(i2c, 0x40)
-    //rom_access(i2c, 0x50)
+    retimer_access(i2c, 0x40, allow_unsigned)
+    //rom_access(i2c, 0x50, RomMode::Dump)
+    //rom_access(i2c, 0x50, RomMode::Restore)
 }
 
-unsafe fn i2c_enable(sideband: Rc<Sideband>) -> i32 {
+unsafe fn i2c_enable(sideband: Rc<Sideband>, allow_unsigned: bool) -> i32 {
     let mut rom_i2c_en = Gpio::new(sideband.clone(), 0x6A, 0x70).unwrap(); // GPP_E1
 
     println!("Set ROM_I2C_EN high");
@@ -514,7 +856,7 @@ unsafe fn i2c_enable(sideband: Rc<Sideband>) -> i32 {
     println!("Sleep 40 ms");
     thread::sleep(time::Duration::from_millis(40));
 
-    let exit_status = i2c_access(sideband);
+    let exit_status = i2c_access(sideband, allow_unsigned);
 
     eprintln!("Set ROM_I2C_EN low");
     rom_i2c_en.set_tx(false);
@@ -522,7 +864,7 @@ unsafe fn i2c_enable(sideband: Rc<Sideband>) -> i32 {
     exit_status
 }
 
-unsafe fn force_power(sideband: Rc<Sideband>) -> i32 {
+unsafe fn force_power(sideband: Rc<Sideband>, allow_unsigned: bool) -> i32 {
     let mut force_power = Gpio::new(sideband.clone(), 0x6E, 0x82).unwrap(); // GPP_A23
 
     println!("Set FORCE_POWER high");
@@ -531,7 +873,7 @@ unsafe fn force_power(sideband: Rc<Sideband>) -> i32 {
     println!("Sleep 40 ms");
     thread::sleep(time::Duration::from_millis(40));
 
-    let exit_status = i2c_enable(sideband);
+    let exit_status = i2c_enable(sideband, allow_unsigned);
 
     eprintln!("Set FORCE_POWER low");
     force_power.set_tx(false);
@@ -542,6 +884,8 @@ unsafe fn force_power(sideband: Rc<Sideband>) -> i32 {
 fn main() {
     //TODO: check model
 
+    let allow_unsigned = env::args().any(|arg| arg == "--allow-unsigned");
+
     unsafe {
         if libc::sched_setscheduler(
             libc::getpid(),
@@ -562,6 +906,33 @@ fn main() {
             }
         };
 
-        process::exit(force_power(sideband));
+        process::exit(force_power(sideband, allow_unsigned));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adjacent_writes_share_one_start() {
+        let a = [0u8; 1];
+        let b = [0u8; 1];
+        let operations = [Operation::Write(&a), Operation::Write(&b)];
+        assert_eq!(transaction_starts(&operations), vec![true, false]);
+    }
+
+    #[test]
+    fn read_then_write_starts_again() {
+        let mut a = [0u8; 1];
+        let b = [0u8; 1];
+        let operations = [Operation::Read(&mut a), Operation::Write(&b)];
+        assert_eq!(transaction_starts(&operations), vec![true, true]);
+    }
+
+    #[test]
+    fn empty_transaction_has_no_starts() {
+        let operations: [Operation; 0] = [];
+        assert!(transaction_starts(&operations).is_empty());
     }
 }